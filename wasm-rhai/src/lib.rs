@@ -1,8 +1,19 @@
 use rhai::packages::{Package, StandardPackage};
 use rhai::{AST, Dynamic, Engine, Scope};
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
+/// A command a script emits for the host to validate/apply; the renderer/TS
+/// side switches on the `t` tag. Schema per variant:
+/// - move: `{"t":"move","dx":i64}`
+/// - anim: `{"t":"anim","name":string}`
+/// - spawn_hitbox: `{"t":"spawn_hitbox","x":i64,"y":i64,"w":i64,"h":i64,"damage":i64}`
+/// - set_hurtbox: `{"t":"set_hurtbox","x":i64,"y":i64,"w":i64,"h":i64}`
+/// - spawn_projectile: `{"t":"spawn_projectile","name":string,"dx":i64,"dy":i64}`
+/// - play_sound: `{"t":"play_sound","name":string}`
+/// - cancel_anim: `{"t":"cancel_anim"}`
 #[derive(serde::Serialize, Clone)]
 #[serde(tag = "t")]
 enum Cmd {
@@ -10,102 +21,512 @@ enum Cmd {
     Move { dx: i64 },
     #[serde(rename = "anim")]
     Anim { name: String },
+    #[serde(rename = "spawn_hitbox")]
+    SpawnHitbox {
+        x: i64,
+        y: i64,
+        w: i64,
+        h: i64,
+        damage: i64,
+    },
+    #[serde(rename = "set_hurtbox")]
+    SetHurtbox { x: i64, y: i64, w: i64, h: i64 },
+    #[serde(rename = "spawn_projectile")]
+    SpawnProjectile { name: String, dx: i64, dy: i64 },
+    #[serde(rename = "play_sound")]
+    PlaySound { name: String },
+    #[serde(rename = "cancel_anim")]
+    CancelAnim,
+}
+
+/// One independently-loaded script: its own engine (so registered fns can
+/// capture this instance's own command buffer), AST, and scope. Letting two
+/// fighters (or a local/remote/rollback-prediction trio) each own an
+/// `Instance` means they never share mutable state.
+struct Instance {
+    engine: Engine,
+    ast: Option<AST>,
+    scope: Scope<'static>,
+    // Scope as it stood right after `load_script_source` ran the script's top-level
+    // init block, before any `tick` call mutated it. `restore_state` rebuilds from
+    // this baseline so state outside `state` (consts, helper globals) stays intact.
+    baseline_scope: Scope<'static>,
+    cmds: Rc<RefCell<Vec<Cmd>>>,
+    // SplitMix64 state for the script's deterministic RNG. Part of the
+    // snapshot so a restored-then-resimulated frame draws the exact same
+    // numbers as it did the first time.
+    rng: Rc<RefCell<u64>>,
+    last_err: Option<String>,
 }
 
 thread_local! {
-    static ENGINE: RefCell<Engine> = RefCell::new(setup_engine());
-    static AST_OBJ: RefCell<Option<AST>> = const { RefCell::new(None) };
-    static SCOPE: RefCell<Scope<'static>> = RefCell::new(Scope::new());
-    static CMDS: RefCell<Vec<Cmd>> = const { RefCell::new(Vec::new()) };
-    static LAST_ERR: RefCell<Option<String>> = const { RefCell::new(None) };
+    static INSTANCES: RefCell<HashMap<u32, Instance>> = RefCell::new(HashMap::new());
+    static NEXT_HANDLE: RefCell<u32> = const { RefCell::new(1) };
+}
+
+/// Advance a SplitMix64 generator in place and return its next output. Pure
+/// 64-bit integer arithmetic only (no floats, no host time), so results are
+/// bit-identical across browsers/targets, which rollback determinism requires.
+fn splitmix64_next(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
 }
 
-fn setup_engine() -> Engine {
+fn setup_engine(cmds: Rc<RefCell<Vec<Cmd>>>, rng: Rc<RefCell<u64>>) -> Engine {
     // Start from raw engine and add the standard package explicitly to avoid
     // pulling in extra host imports. This enables operators like '&' and helpers like 'is_def'.
     let mut eng = Engine::new_raw();
     eng.register_global_module(StandardPackage::new().as_shared_module());
     // Capability-based API: record commands
-    eng.register_fn("move", |dx: i64| {
-        CMDS.with(|c| c.borrow_mut().push(Cmd::Move { dx }));
-    });
-    eng.register_fn("anim_play", |name: &str| {
-        CMDS.with(|c| {
-            c.borrow_mut().push(Cmd::Anim {
+    {
+        let cmds = cmds.clone();
+        eng.register_fn("move", move |dx: i64| {
+            cmds.borrow_mut().push(Cmd::Move { dx });
+        });
+    }
+    {
+        let cmds = cmds.clone();
+        eng.register_fn("anim_play", move |name: &str| {
+            cmds.borrow_mut().push(Cmd::Anim {
                 name: name.to_string(),
-            })
+            });
         });
-    });
+    }
+    {
+        let cmds = cmds.clone();
+        eng.register_fn(
+            "spawn_hitbox",
+            move |x: i64, y: i64, w: i64, h: i64, damage: i64| {
+                cmds.borrow_mut().push(Cmd::SpawnHitbox {
+                    x,
+                    y,
+                    w,
+                    h,
+                    damage,
+                });
+            },
+        );
+    }
+    {
+        let cmds = cmds.clone();
+        eng.register_fn("set_hurtbox", move |x: i64, y: i64, w: i64, h: i64| {
+            cmds.borrow_mut().push(Cmd::SetHurtbox { x, y, w, h });
+        });
+    }
+    {
+        let cmds = cmds.clone();
+        eng.register_fn(
+            "spawn_projectile",
+            move |name: &str, dx: i64, dy: i64| {
+                cmds.borrow_mut().push(Cmd::SpawnProjectile {
+                    name: name.to_string(),
+                    dx,
+                    dy,
+                });
+            },
+        );
+    }
+    {
+        let cmds = cmds.clone();
+        eng.register_fn("play_sound", move |name: &str| {
+            cmds.borrow_mut().push(Cmd::PlaySound {
+                name: name.to_string(),
+            });
+        });
+    }
+    {
+        let cmds = cmds.clone();
+        eng.register_fn("cancel_anim", move || {
+            cmds.borrow_mut().push(Cmd::CancelAnim);
+        });
+    }
+    // Deterministic RNG: same seed + same call sequence always draws the same
+    // numbers, so scripts can do throw mixups/AI variation without breaking
+    // rollback re-simulation.
+    // Masked to the non-negative i64 range so a script's `rng() % n` can't go
+    // negative; use `rng_range` when a specific bound is wanted.
+    {
+        let rng = rng.clone();
+        eng.register_fn("rng", move || -> i64 {
+            (splitmix64_next(&mut rng.borrow_mut()) & i64::MAX as u64) as i64
+        });
+    }
+    {
+        let rng = rng.clone();
+        eng.register_fn("rng_range", move |lo: i64, hi: i64| -> i64 {
+            if hi <= lo {
+                return lo;
+            }
+            // i128 avoids overflow for extreme bounds like (i64::MIN, i64::MAX),
+            // whose span doesn't fit in i64 or u64 arithmetic.
+            let span = (hi as i128 - lo as i128) as u128;
+            let draw = (splitmix64_next(&mut rng.borrow_mut()) as u128) % span;
+            (lo as i128 + draw as i128) as i64
+        });
+    }
     eng.set_max_operations(50_000);
     eng
 }
 
+/// Create a new script instance and return its opaque handle. The handle is
+/// what every other export takes to identify which fighter's script to
+/// drive; the host is expected to call `destroy_instance` once done with it.
+#[wasm_bindgen]
+pub fn create_instance() -> u32 {
+    let handle = NEXT_HANDLE.with(|h| {
+        let mut h = h.borrow_mut();
+        let cur = *h;
+        *h += 1;
+        cur
+    });
+    let cmds = Rc::new(RefCell::new(Vec::new()));
+    let rng = Rc::new(RefCell::new(0));
+    let instance = Instance {
+        engine: setup_engine(cmds.clone(), rng.clone()),
+        ast: None,
+        scope: Scope::new(),
+        baseline_scope: Scope::new(),
+        cmds,
+        rng,
+        last_err: None,
+    };
+    INSTANCES.with(|m| m.borrow_mut().insert(handle, instance));
+    handle
+}
+
+/// Seed the instance's deterministic RNG. Call before ticking (or after a
+/// restore, for a fresh match) so `rng`/`rng_range` draws are reproducible
+/// across the network and across rollback re-simulation.
 #[wasm_bindgen]
-pub fn load_script_source(src: &str) -> bool {
-    match ENGINE.with(|e| e.borrow().compile(src)) {
-        Ok(ast) => {
-            // Reset scope and run top-level statements once to allow global init blocks
-            // like `if !is_def(state) { ... }` to execute before the first tick.
-            let mut scope = Scope::new();
-            let res = ENGINE.with(|e| e.borrow().eval_ast_with_scope::<Dynamic>(&mut scope, &ast));
-            match res {
-                Ok(_) => {
-                    SCOPE.with(|s| *s.borrow_mut() = scope);
-                    AST_OBJ.with(|o| *o.borrow_mut() = Some(ast));
-                    true
+pub fn seed_rng(handle: u32, seed: u64) {
+    INSTANCES.with(|m| {
+        if let Some(inst) = m.borrow().get(&handle) {
+            *inst.rng.borrow_mut() = seed;
+        }
+    });
+}
+
+/// Tear down a script instance and free its engine/scope/command buffer.
+#[wasm_bindgen]
+pub fn destroy_instance(handle: u32) {
+    INSTANCES.with(|m| {
+        m.borrow_mut().remove(&handle);
+    });
+}
+
+#[wasm_bindgen]
+pub fn load_script_source(handle: u32, src: &str) -> bool {
+    INSTANCES.with(|m| {
+        let mut m = m.borrow_mut();
+        let Some(inst) = m.get_mut(&handle) else {
+            return false;
+        };
+        match inst.engine.compile(src) {
+            Ok(ast) => {
+                // Reset scope and run top-level statements once to allow global init blocks
+                // like `if !is_def(state) { ... }` to execute before the first tick.
+                let mut scope = Scope::new();
+                match inst
+                    .engine
+                    .eval_ast_with_scope::<Dynamic>(&mut scope, &ast)
+                {
+                    Ok(_) => {
+                        inst.baseline_scope = scope.clone();
+                        inst.scope = scope;
+                        inst.ast = Some(ast);
+                        true
+                    }
+                    Err(err) => {
+                        inst.last_err = Some(format!("{}", err));
+                        false
+                    }
                 }
+            }
+            Err(err) => {
+                inst.last_err = Some(format!("{}", err));
+                false
+            }
+        }
+    })
+}
+
+/// Ticks the script, first publishing `world_json` (own/opponent position,
+/// health, distance, etc.) into the scope as a `world` map so scripts can
+/// read e.g. `world.opponent_x`.
+///
+/// The scope is mutated in place instead of cloned each call to avoid a
+/// per-frame allocation. `Engine::call_fn` still builds its own internal
+/// caches per call; this tree has no pinned Rhai version (no `Cargo.toml`
+/// to check against) to confirm whether a public cache-reuse entry point
+/// exists, so that half of the request is not implemented here - verify
+/// against the actual pinned `rhai` version before relying on this claim.
+#[wasm_bindgen]
+pub fn tick_and_get_commands(handle: u32, frame: u32, input_mask: u32, world_json: &str) -> String {
+    let world: Dynamic = match serde_json::from_str::<serde_json::Value>(world_json) {
+        Ok(value) => rhai::serde::to_dynamic(&value).unwrap_or(Dynamic::UNIT),
+        Err(_) => Dynamic::UNIT,
+    };
+    INSTANCES.with(|m| {
+        let mut m = m.borrow_mut();
+        let Some(inst) = m.get_mut(&handle) else {
+            return "[]".into();
+        };
+        let ok = if let Some(ast) = inst.ast.as_ref() {
+            inst.scope.set_or_push("INPUT", input_mask as i64);
+            inst.scope.set_or_push("world", world);
+            let r = inst.engine.call_fn::<Dynamic>(
+                &mut inst.scope,
+                ast,
+                "tick",
+                (frame as i64, input_mask as i64),
+            );
+            match r {
+                Ok(_) => true,
                 Err(err) => {
-                    LAST_ERR.with(|le| *le.borrow_mut() = Some(format!("{}", err)));
+                    inst.last_err = Some(format!("{}", err));
                     false
                 }
             }
-        }
-        Err(err) => {
-            LAST_ERR.with(|le| *le.borrow_mut() = Some(format!("{}", err)));
+        } else {
             false
+        };
+        let out = {
+            let mut c = inst.cmds.borrow_mut();
+            let v = c.clone();
+            c.clear();
+            v
+        };
+        if ok {
+            serde_json::to_string(&out).unwrap_or_else(|_| "[]".into())
+        } else {
+            "[]".into()
         }
+    })
+}
+
+/// Run `n` ticks back-to-back (each with empty input/world) and return the
+/// elapsed time in milliseconds, so the cost of the persistent-scope tick
+/// path is measurable from JS instead of guessed at.
+#[wasm_bindgen]
+pub fn benchmark_ticks(handle: u32, n: u32) -> f64 {
+    let start = js_sys::Date::now();
+    for frame in 0..n {
+        tick_and_get_commands(handle, frame, 0, "{}");
     }
+    js_sys::Date::now() - start
 }
 
 #[wasm_bindgen]
-pub fn tick_and_get_commands(frame: u32, input_mask: u32) -> String {
-    let ok = ENGINE.with(|e| {
-        AST_OBJ.with(|o| {
-            if let Some(ast) = o.borrow().as_ref() {
-                let mut scope = SCOPE.with(|s| s.borrow().clone());
-                scope.set_or_push("INPUT", input_mask as i64);
-                let r = e.borrow().call_fn::<Dynamic>(
-                    &mut scope,
-                    ast,
-                    "tick",
-                    (frame as i64, input_mask as i64),
-                );
-                SCOPE.with(|s| *s.borrow_mut() = scope);
-                match r {
-                    Ok(_) => true,
-                    Err(err) => {
-                        LAST_ERR.with(|le| *le.borrow_mut() = Some(format!("{}", err)));
-                        false
-                    }
+pub fn take_last_error(handle: u32) -> String {
+    INSTANCES.with(|m| {
+        m.borrow_mut()
+            .get_mut(&handle)
+            .and_then(|inst| inst.last_err.take())
+            .unwrap_or_default()
+    })
+}
+
+/// Everything needed to resume a script deterministically: the persistent
+/// `state` map plus the RNG counter, so a restored-then-resimulated frame
+/// draws identical random numbers to the original run.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Snapshot {
+    state: Dynamic,
+    rng: u64,
+}
+
+/// Serialize the script's persistent `state` variable and RNG counter for
+/// rollback netcode.
+///
+/// Only `state` is captured from the scope: scripts are expected to keep all
+/// mutable game state in that single map (per the `is_def(state)` init
+/// convention), so a snapshot is just `state` round-tripped through Rhai's
+/// serde bridge alongside the RNG counter. The AST and any other scope
+/// entries are left for `restore_state` to rebuild from the
+/// post-`load_script_source` baseline.
+#[wasm_bindgen]
+pub fn snapshot_state(handle: u32) -> Vec<u8> {
+    INSTANCES.with(|m| {
+        let m = m.borrow();
+        let Some(inst) = m.get(&handle) else {
+            return Vec::new();
+        };
+        let snapshot = Snapshot {
+            state: inst
+                .scope
+                .get_value::<Dynamic>("state")
+                .unwrap_or(Dynamic::UNIT),
+            rng: *inst.rng.borrow(),
+        };
+        serde_json::to_vec(&snapshot).unwrap_or_default()
+    })
+}
+
+/// Restore `state` and the RNG counter from bytes produced by
+/// `snapshot_state`, rebuilding the scope from the baseline captured at
+/// `load_script_source` time. Clears any pending command buffer so the next
+/// `tick_and_get_commands` starts from a clean slate, matching the rollback
+/// re-simulation invariant that re-running the same `(frame, input_mask)`
+/// after a restore reproduces the same commands.
+#[wasm_bindgen]
+pub fn restore_state(handle: u32, bytes: &[u8]) -> bool {
+    let snapshot: Snapshot = match serde_json::from_slice(bytes) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            INSTANCES.with(|m| {
+                if let Some(inst) = m.borrow_mut().get_mut(&handle) {
+                    inst.last_err = Some(format!("{}", err));
                 }
-            } else {
-                false
+            });
+            return false;
+        }
+    };
+    INSTANCES.with(|m| {
+        let mut m = m.borrow_mut();
+        let Some(inst) = m.get_mut(&handle) else {
+            return false;
+        };
+        let mut scope = inst.baseline_scope.clone();
+        scope.set_or_push("state", snapshot.state);
+        inst.scope = scope;
+        *inst.rng.borrow_mut() = snapshot.rng;
+        inst.cmds.borrow_mut().clear();
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        if !is_def(state) {
+            state = #{ x: 0 };
+        }
+        fn tick(frame, input) {
+            state.x = state.x + 1;
+            move(state.x);
+        }
+    "#;
+
+    #[test]
+    fn restore_then_retick_reproduces_commands() {
+        let handle = create_instance();
+        assert!(load_script_source(handle, SCRIPT));
+
+        tick_and_get_commands(handle, 0, 0, "{}");
+        tick_and_get_commands(handle, 1, 0, "{}");
+        let snapshot = snapshot_state(handle);
+
+        let first_run = tick_and_get_commands(handle, 2, 7, "{}");
+
+        assert!(restore_state(handle, &snapshot));
+        let second_run = tick_and_get_commands(handle, 2, 7, "{}");
+
+        assert_eq!(first_run, second_run);
+        destroy_instance(handle);
+    }
+
+    #[test]
+    fn restore_then_retick_reproduces_rng_draws() {
+        const RNG_SCRIPT: &str = r#"
+            if !is_def(state) {
+                state = #{ x: 0 };
             }
-        })
-    });
-    let out = CMDS.with(|c| {
-        let v = c.borrow().clone();
-        c.borrow_mut().clear();
-        v
-    });
-    if ok {
-        serde_json::to_string(&out).unwrap_or_else(|_| "[]".into())
-    } else {
-        "[]".into()
+            fn tick(frame, input) {
+                move(rng_range(0, 1000000));
+            }
+        "#;
+
+        let handle = create_instance();
+        seed_rng(handle, 42);
+        assert!(load_script_source(handle, RNG_SCRIPT));
+
+        tick_and_get_commands(handle, 0, 0, "{}");
+        tick_and_get_commands(handle, 1, 0, "{}");
+        let snapshot = snapshot_state(handle);
+
+        let first_run = tick_and_get_commands(handle, 2, 0, "{}");
+
+        assert!(restore_state(handle, &snapshot));
+        let second_run = tick_and_get_commands(handle, 2, 0, "{}");
+
+        assert_eq!(first_run, second_run);
+        destroy_instance(handle);
     }
-}
 
-#[wasm_bindgen]
-pub fn take_last_error() -> String {
-    LAST_ERR.with(|le| le.borrow_mut().take().unwrap_or_default())
+    #[test]
+    fn sequential_ticks_accumulate_state_in_place() {
+        let handle = create_instance();
+        assert!(load_script_source(handle, SCRIPT));
+
+        let commands: Vec<String> = (0..3)
+            .map(|frame| tick_and_get_commands(handle, frame, 0, "{}"))
+            .collect();
+
+        assert_eq!(
+            commands,
+            vec![
+                r#"[{"t":"move","dx":1}]"#,
+                r#"[{"t":"move","dx":2}]"#,
+                r#"[{"t":"move","dx":3}]"#,
+            ]
+        );
+        destroy_instance(handle);
+    }
+
+    #[test]
+    fn two_instances_do_not_share_state() {
+        const OTHER_SCRIPT: &str = r#"
+            if !is_def(state) {
+                state = #{ x: 100 };
+            }
+            fn tick(frame, input) {
+                state.x = state.x - 10;
+                move(state.x);
+            }
+        "#;
+
+        let a = create_instance();
+        let b = create_instance();
+        assert!(load_script_source(a, SCRIPT));
+        assert!(load_script_source(b, OTHER_SCRIPT));
+        seed_rng(a, 1);
+        seed_rng(b, 2);
+
+        let a_out = tick_and_get_commands(a, 0, 0, "{}");
+        let b_out = tick_and_get_commands(b, 0, 0, "{}");
+        assert_eq!(a_out, r#"[{"t":"move","dx":1}]"#);
+        assert_eq!(b_out, r#"[{"t":"move","dx":90}]"#);
+
+        // Ticking `a` again must not have been perturbed by `b`'s tick above.
+        let a_out_2 = tick_and_get_commands(a, 1, 0, "{}");
+        assert_eq!(a_out_2, r#"[{"t":"move","dx":2}]"#);
+
+        destroy_instance(a);
+        destroy_instance(b);
+    }
+
+    #[test]
+    fn tick_observes_world_state() {
+        const WORLD_SCRIPT: &str = r#"
+            if !is_def(state) {
+                state = #{ x: 0 };
+            }
+            fn tick(frame, input) {
+                move(world.opponent_x - world.self_x);
+            }
+        "#;
+
+        let handle = create_instance();
+        assert!(load_script_source(handle, WORLD_SCRIPT));
+
+        let out = tick_and_get_commands(handle, 0, 0, r#"{"self_x":10,"opponent_x":45}"#);
+        assert_eq!(out, r#"[{"t":"move","dx":35}]"#);
+
+        destroy_instance(handle);
+    }
 }